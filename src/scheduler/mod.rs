@@ -26,16 +26,24 @@
 
 //! Interface to the scheduler
 
+use core::future::Future;
+use consts::NORMAL_PRIO;
+
 /// task control block
 pub mod task;
 mod scheduler;
 
-static mut SCHEDULER: scheduler::Scheduler = scheduler::Scheduler::new();
-
-/// Initialite module, must be called once, and only once
+/// Initialite module, must be called once on the boot core, and only once
 pub fn init() {
 	unsafe {
-		SCHEDULER.add_idle_task();
+		scheduler::init();
+	}
+}
+
+/// Bring up the scheduler of a secondary core
+pub fn init_core() {
+	unsafe {
+		scheduler::init_core();
 	}
 }
 
@@ -43,7 +51,54 @@ pub fn init() {
 #[inline(always)]
 pub fn spawn(func: extern fn()) -> Result<task::TaskId, scheduler::SchedulerError> {
 	unsafe {
-		SCHEDULER.spawn(func)
+		scheduler::core_scheduler().spawn(func, NORMAL_PRIO)
+	}
+}
+
+/// Spawn an async task that drives `future` to completion on top of the
+/// scheduler's blocking primitives
+#[inline(always)]
+pub fn spawn_async<F>(future: F) -> Result<task::TaskId, scheduler::SchedulerError>
+	where F: Future<Output = ()> + 'static {
+	unsafe {
+		scheduler::core_scheduler().spawn_async(future)
+	}
+}
+
+/// Account one timer tick against the running task's time slice.
+///
+/// Called by the architecture's timer IRQ handler on every tick. It never
+/// switches tasks directly; it only flags a pending reschedule that
+/// `check_preemption` acts on once the interrupt frame is unwound.
+#[inline(always)]
+pub fn timer_tick() {
+	unsafe {
+		scheduler::core_scheduler().timer_tick()
+	}
+}
+
+/// Perform a pending preemption on the IRQ return path, after
+/// `irq_nested_enable`.
+#[inline(always)]
+pub fn check_preemption() {
+	unsafe {
+		scheduler::core_scheduler().check_preemption()
+	}
+}
+
+/// Request termination of the task `tid` from another task
+#[inline(always)]
+pub fn kill(tid: task::TaskId) {
+	unsafe {
+		scheduler::core_scheduler().kill(tid)
+	}
+}
+
+/// Block the current task for at least `ticks` timer ticks
+#[inline(always)]
+pub fn sleep(ticks: u64) {
+	unsafe {
+		scheduler::core_scheduler().sleep(ticks)
 	}
 }
 
@@ -51,7 +106,7 @@ pub fn spawn(func: extern fn()) -> Result<task::TaskId, scheduler::SchedulerErro
 #[inline(always)]
 pub fn reschedule() {
 	unsafe {
-		SCHEDULER.reschedule()
+		scheduler::core_scheduler().reschedule()
 	}
 }
 
@@ -59,7 +114,51 @@ pub fn reschedule() {
 #[inline(always)]
 pub fn do_exit() {
 	unsafe {
-		SCHEDULER.exit();
+		scheduler::core_scheduler().exit();
+	}
+}
+
+/// Terminate the current running task and record an exit code that can be
+/// read back through `join`.
+#[inline(always)]
+pub fn exit_with(code: i32) {
+	unsafe {
+		scheduler::core_scheduler().exit_with(code);
+	}
+}
+
+/// Wait for the task `tid` to finish and return its exit code, or `None` if
+/// `tid` is the calling task, the task is unknown, or its stashed exit code
+/// is no longer available.
+#[inline(always)]
+pub fn join(tid: task::TaskId) -> Option<i32> {
+	unsafe {
+		scheduler::core_scheduler().join(tid)
+	}
+}
+
+/// Store `value` in the local-storage slot `key` of the current task
+#[inline(always)]
+pub fn set_task_local(key: usize, value: usize) {
+	unsafe {
+		scheduler::core_scheduler().set_task_local(key, value)
+	}
+}
+
+/// Read the local-storage slot `key` of the current task
+#[inline(always)]
+pub fn get_task_local(key: usize) -> usize {
+	unsafe {
+		scheduler::core_scheduler().get_task_local(key)
+	}
+}
+
+/// Register a destructor for the local-storage slot `key` of the current task,
+/// run with the slot's value when the task exits
+#[inline(always)]
+pub fn set_task_local_dtor(key: usize, dtor: extern fn(usize)) {
+	unsafe {
+		scheduler::core_scheduler().set_task_local_dtor(key, dtor)
 	}
 }
 
@@ -67,6 +166,6 @@ pub fn do_exit() {
 #[inline(always)]
 pub fn get_current_taskid() -> task::TaskId {
 	unsafe {
-		SCHEDULER.get_current_taskid()
+		scheduler::core_scheduler().get_current_taskid()
 	}
 }
\ No newline at end of file
@@ -21,19 +21,178 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::future::Future;
+use core::mem;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 use core::ptr::Shared;
 use scheduler::task::*;
 use arch::irq::{irq_nested_enable,irq_nested_disable};
+use arch::processor::core_id;
 use logging::*;
 use consts::*;
 use synch::spinlock::*;
 use alloc::VecDeque;
 use alloc::boxed::Box;
+use alloc::alloc::{alloc, Layout};
 use alloc::btree_map::*;
+use core::ptr;
+
+/// Upper bound on the number of live tasks. Keeps the id space and the task
+/// budget from being exhausted, so `get_tid`/`spawn` can fail gracefully
+/// instead of spinning forever.
+const MAX_TASKS: usize = 1024;
 
 static TID_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
+/// Errors that can occur while creating a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulerError {
+	/// the configured task budget (`MAX_TASKS`) is exhausted
+	TooManyTasks,
+	/// the task control block could not be allocated
+	OutOfMemory,
+	/// the kernel stack could not be allocated
+	StackAllocationFailed
+}
+
+/// Allocate a `Box<T>` on the heap, returning `SchedulerError::OutOfMemory`
+/// instead of aborting when the allocator is exhausted.
+fn try_box<T>(value: T) -> Result<Box<T>, SchedulerError> {
+	let layout = Layout::new::<T>();
+
+	if layout.size() == 0 {
+		return Ok(Box::new(value));
+	}
+
+	unsafe {
+		let ptr = alloc(layout) as *mut T;
+		if ptr.is_null() {
+			return Err(SchedulerError::OutOfMemory);
+		}
+
+		ptr::write(ptr, value);
+		Ok(Box::from_raw(ptr))
+	}
+}
+
+/// monotonic timer tick counter, advanced on every `timer_tick`
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// number of cores that have brought up their scheduler so far
+static NO_CORES: AtomicUsize = AtomicUsize::new(0);
+
+/// map between task id and task controll block, shared by all cores
+static TASKS: SpinlockIrqSave<Option<BTreeMap<TaskId, Shared<Task>>>> = SpinlockIrqSave::new(None);
+/// tasks, which are finished and can be released, shared by all cores
+static FINISHED_TASKS: SpinlockIrqSave<Option<VecDeque<TaskId>>> = SpinlockIrqSave::new(None);
+/// exit codes of finished tasks whose control block has already been released,
+/// kept so that a `join` arriving after the release can still read the code
+static FINISHED_CODES: SpinlockIrqSave<Option<BTreeMap<TaskId, i32>>> = SpinlockIrqSave::new(None);
+/// sleeping tasks, keyed by the absolute tick at which they become ready
+static SLEEPING_TASKS: SpinlockIrqSave<Option<BTreeMap<u64, VecDeque<Shared<Task>>>>> = SpinlockIrqSave::new(None);
+
+/// one scheduler instance per core, indexed by `core_id`
+static mut SCHEDULERS: [Scheduler; MAX_CORES] = [Scheduler::new(); MAX_CORES];
+
+/// Reference to the scheduler of the calling core.
+#[inline(always)]
+pub unsafe fn core_scheduler() -> &'static mut Scheduler {
+	&mut SCHEDULERS[core_id()]
+}
+
+/// Reference to the scheduler of a specific core.
+#[inline(always)]
+unsafe fn scheduler(core: usize) -> &'static mut Scheduler {
+	&mut SCHEDULERS[core]
+}
+
+/// `RawWaker` vtable identifying a task by its `TaskId`. The id (a plain
+/// integer) is stored in the data pointer rather than a `*const Task`, so a
+/// waker that outlives its task never dereferences a freed control block:
+/// `wake` looks the id up in the shared table and is a no-op if the task is
+/// already gone.
+static WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
+	clone_waker, wake_waker, wake_waker, drop_waker);
+
+fn raw_waker(tid: TaskId) -> RawWaker {
+	RawWaker::new(tid.into() as *const (), &WAKER_VTABLE)
+}
+
+unsafe fn clone_waker(ptr: *const ()) -> RawWaker {
+	raw_waker(TaskId::from(ptr as usize))
+}
+
+unsafe fn wake_waker(ptr: *const ()) {
+	core_scheduler().wakeup_task_by_id(TaskId::from(ptr as usize));
+}
+
+unsafe fn drop_waker(_ptr: *const ()) {
+	// the waker only carries a task id, there is nothing to release
+}
+
+/// Build a `Waker` that resumes the task `tid` when woken.
+unsafe fn waker_for(tid: TaskId) -> Waker {
+	Waker::from_raw(raw_waker(tid))
+}
+
+/// Entry point of every async task: poll the task's future to completion,
+/// blocking the task whenever it returns `Pending`.
+extern fn async_task_entry() {
+	loop {
+		let (future, task) = match unsafe { core_scheduler().current_future() } {
+			Some(state) => state,
+			None => unsafe { super::do_exit(); unreachable!() }
+		};
+
+		let waker = unsafe { waker_for(task.as_ref().id) };
+		let mut context = Context::from_waker(&waker);
+
+		let poll = unsafe { Pin::new_unchecked(&mut *future).poll(&mut context) };
+		match poll {
+			Poll::Ready(()) => unsafe { super::do_exit(); },
+			Poll::Pending => unsafe {
+				core_scheduler().block_current_task();
+				core_scheduler().reschedule();
+			}
+		}
+	}
+}
+
+/// Initialize the shared task tables and bring up the boot core's scheduler.
+/// Must be called once on the boot core before any other core is started.
+pub unsafe fn init() {
+	*FINISHED_TASKS.lock() = Some(VecDeque::new());
+	*FINISHED_CODES.lock() = Some(BTreeMap::new());
+	*TASKS.lock() = Some(BTreeMap::new());
+	*SLEEPING_TASKS.lock() = Some(BTreeMap::new());
+
+	core_scheduler().add_idle_task();
+}
+
+/// Bring up a secondary core's scheduler. The shared tables are already
+/// initialized by `init` on the boot core.
+pub unsafe fn init_core() {
+	core_scheduler().add_idle_task();
+}
+
+/// Pick the core with the shortest combined ready queue for a new task.
+unsafe fn least_loaded_core() -> usize {
+	let mut best = 0;
+	let mut best_load = ::core::usize::MAX;
+
+	for core in 0..NO_CORES.load(Ordering::SeqCst) {
+		let load = scheduler(core).load();
+		if load < best_load {
+			best_load = load;
+			best = core;
+		}
+	}
+
+	best
+}
+
 extern {
 	pub fn switch(old_stack: *const u64, new_stack: u64);
 
@@ -44,16 +203,14 @@ extern {
 }
 
 pub struct Scheduler {
-	/// task id which is currently running
+	/// task id which is currently running on this core
 	current_tid: TaskId,
-	/// id of the idle task
+	/// id of this core's idle task
 	idle_tid: TaskId,
-	/// queues of tasks, which are ready
+	/// this core's queues of tasks, which are ready
 	ready_queues: SpinlockIrqSave<[TaskQueue; NO_PRIORITIES]>,
-	/// queue of tasks, which are finished and can be released
-	finished_tasks: SpinlockIrqSave<Option<VecDeque<TaskId>>>,
-	/// map between task id and task controll block
-	tasks: SpinlockIrqSave<Option<BTreeMap<TaskId, Shared<Task>>>>
+	/// set by the timer IRQ when the running task has used up its time slice
+	reschedule_pending: AtomicBool
 }
 
 impl Scheduler {
@@ -62,70 +219,104 @@ impl Scheduler {
 			current_tid: TaskId::from(0),
 			idle_tid: TaskId::from(0),
 			ready_queues: SpinlockIrqSave::new([TaskQueue::new(); NO_PRIORITIES]),
-			finished_tasks: SpinlockIrqSave::new(None),
-			tasks: SpinlockIrqSave::new(None)
+			reschedule_pending: AtomicBool::new(false)
+		}
+	}
+
+	/// Combined length of all ready queues of this core.
+	fn load(&self) -> usize {
+		let guard = self.ready_queues.lock();
+		let mut total = 0;
+
+		for i in 0..NO_PRIORITIES {
+			total += guard[i].len();
 		}
+
+		total
 	}
 
-	fn get_tid(&self) -> TaskId {
+	fn get_tid(&self) -> Result<TaskId, SchedulerError> {
+		// refuse to hand out an id once the task budget is used up, otherwise
+		// the loop below could spin forever searching for a free slot
+		if TASKS.lock().as_ref().unwrap().len() >= MAX_TASKS {
+			return Err(SchedulerError::TooManyTasks);
+		}
+
 		loop {
 			let id = TaskId::from(TID_COUNTER.fetch_add(1, Ordering::SeqCst));
 
-			if self.tasks.lock().as_ref().unwrap().contains_key(&id) == false {
-				return id;
+			if TASKS.lock().as_ref().unwrap().contains_key(&id) == false {
+				return Ok(id);
 			}
 		}
 	}
 
 	pub unsafe fn add_idle_task(&mut self) {
-		// idle task is the first task for the scheduler => initialize queues and btree
+		// the idle task is the first task of a core => give this core an id
+		let core = NO_CORES.fetch_add(1, Ordering::SeqCst);
 
-		// initialize vector of queues
-		*self.finished_tasks.lock() = Some(VecDeque::new());
-		*self.tasks.lock() = Some(BTreeMap::new());
-		self.idle_tid = self.get_tid();
+		self.idle_tid = self.get_tid().expect("unable to allocate an id for the idle task");
 		self.current_tid = self.idle_tid;
 
-		// boot task is implicitly task 0 and and the idle task of core 0
-		let idle_task = Box::new(Task::new(self.idle_tid, TaskStatus::TaskIdle, LOW_PRIO));
+		// boot task is implicitly the idle task of its core
+		let mut idle_task = Box::new(Task::new(self.idle_tid, TaskStatus::TaskIdle, LOW_PRIO));
+		idle_task.allocate_stack().expect("unable to allocate the idle task stack");
 
 		// replace temporary boot stack by the kernel stack of the boot task
-		replace_boot_stack((*idle_task.stack).bottom());
+		replace_boot_stack(idle_task.stack.as_ref().unwrap().bottom());
 
-		self.tasks.lock().as_mut().unwrap().insert(self.idle_tid,
+		TASKS.lock().as_mut().unwrap().insert(self.idle_tid,
 			Shared::new_unchecked(Box::into_raw(idle_task)));
+
+		info!("core {} runs idle task {}", core, self.idle_tid);
 	}
 
-	pub unsafe fn spawn(&mut self, func: extern fn(), prio: Priority) -> TaskId {
+	pub unsafe fn spawn(&mut self, func: extern fn(), prio: Priority) -> Result<TaskId, SchedulerError> {
 		let tid: TaskId;
+		// balance the load by enqueuing the new task on the least busy core
+		let core = least_loaded_core();
 
 		// do we have finished a task? => reuse it
-		match self.finished_tasks.lock().as_mut().unwrap().pop_front() {
+		match FINISHED_TASKS.lock().as_mut().unwrap().pop_front() {
 			None => {
 				debug!("create new task control block");
-				tid = self.get_tid();
-				let mut task = Box::new(Task::new(tid, TaskStatus::TaskReady, prio));
+				tid = self.get_tid()?;
+				// a fresh id cannot carry a stale exit code, but clear it
+				// defensively so the stash never outlives the id it belongs to
+				FINISHED_CODES.lock().as_mut().unwrap().remove(&tid);
+				let mut task = try_box(Task::new(tid, TaskStatus::TaskReady, prio))?;
 
-				task.create_stack_frame(func);
+				task.create_stack_frame(func)?;
 
 				let shared_task = &mut Shared::new_unchecked(Box::into_raw(task));
-				self.ready_queues.lock()[prio.into() as usize].push_back(shared_task);
-				self.tasks.lock().as_mut().unwrap().insert(tid, *shared_task);
+				scheduler(core).ready_queues.lock()[prio.into() as usize].push_back(shared_task);
+				TASKS.lock().as_mut().unwrap().insert(tid, *shared_task);
 			},
 			Some(id) => {
 				debug!("resuse existing task control block");
 
 				tid = id;
-				match self.tasks.lock().as_mut().unwrap().get_mut(&tid) {
+				// the id lives on in the reused control block, so drop any exit
+				// code stashed for its previous life before it starts running
+				// again, otherwise a join would read the old code (see join)
+				FINISHED_CODES.lock().as_mut().unwrap().remove(&tid);
+				match TASKS.lock().as_mut().unwrap().get_mut(&tid) {
 					Some(task) => {
 						// reset old task and setup stack frame
 						task.as_mut().status = TaskStatus::TaskReady;
 						task.as_mut().prio = prio;
 						task.as_mut().last_stack_pointer = 0;
+						task.as_mut().exit_code = None;
+						task.as_mut().kill_pending = false;
+						task.as_mut().waiters.clear();
+						task.as_mut().local_storage = [0; TASK_LOCAL_SLOTS];
+						task.as_mut().local_destructors = [None; TASK_LOCAL_SLOTS];
+						task.as_mut().future = None;
+						task.as_mut().waker = None;
 
-						task.as_mut().create_stack_frame(func);
+						task.as_mut().create_stack_frame(func)?;
 
-						self.ready_queues.lock()[prio.into() as usize].push_back(task);
+						scheduler(core).ready_queues.lock()[prio.into() as usize].push_back(task);
 					},
 					None => panic!("didn't find task")
 				}
@@ -134,14 +325,83 @@ impl Scheduler {
 
 		info!("create task with id {}", tid);
 
-		tid
+		Ok(tid)
+	}
+
+	/// Spawn an async task: the `future` is boxed and driven by a poll loop
+	/// task (see `async_task_entry`). The future advances until it is `Ready`,
+	/// blocking the task on every `Pending` and resuming it through the waker.
+	pub unsafe fn spawn_async<F>(&mut self, future: F) -> Result<TaskId, SchedulerError>
+		where F: Future<Output = ()> + 'static {
+		let boxed: Box<Future<Output = ()>> = Box::new(future);
+		let raw = Box::into_raw(boxed);
+
+		let tid = match self.spawn(async_task_entry, NORMAL_PRIO) {
+			Ok(tid) => tid,
+			Err(err) => {
+				// the poll loop task could not be created => release the future
+				drop(Box::from_raw(raw));
+				return Err(err);
+			}
+		};
+
+		// attach the future and its waker to the freshly created task
+		if let Some(task) = TASKS.lock().as_mut().unwrap().get_mut(&tid) {
+			task.as_mut().future = Some(raw);
+			task.as_mut().waker = Some(waker_for(tid));
+		}
+
+		Ok(tid)
 	}
 
+	/// Return the running task's future pointer together with its `Shared`
+	/// handle, or `None` if it is not an async task.
+	unsafe fn current_future(&self) -> Option<(*mut (Future<Output = ()> + 'static), Shared<Task>)> {
+		let id = self.current_tid;
+
+		match TASKS.lock().as_ref().unwrap().get(&id) {
+			Some(task) => task.as_ref().future.map(|future| (future, *task)),
+			None => None
+		}
+	}
+
+	/// Drop the boxed future and waker of task `id`, if any.
+	unsafe fn drop_async_state(&mut self, id: TaskId) {
+
+		let future = {
+			let mut guard = TASKS.lock();
+			match guard.as_mut().unwrap().get_mut(&id) {
+				Some(task) => {
+					task.as_mut().waker = None;
+					task.as_mut().future.take()
+				},
+				None => None
+			}
+		};
+
+		if let Some(future) = future {
+			drop(Box::from_raw(future));
+		}
+	}
+
+	#[inline(always)]
 	pub unsafe fn exit(&mut self) {
-		match self.tasks.lock().as_mut().unwrap().get_mut(&self.current_tid) {
+		self.exit_with(0);
+	}
+
+	pub unsafe fn exit_with(&mut self, code: i32) {
+		// run any registered destructors for this task's local-storage slots
+		// before the control block is flagged as finished
+		self.drop_task_locals(self.current_tid);
+
+		// an async task owns its boxed future => release it and its waker
+		self.drop_async_state(self.current_tid);
+
+		match TASKS.lock().as_mut().unwrap().get_mut(&self.current_tid) {
 			Some(task) => {
 				if task.as_ref().status != TaskStatus::TaskIdle {
-					info!("finish task with id {}", self.current_tid);
+					info!("finish task with id {} (exit code {})", self.current_tid, code);
+					task.as_mut().exit_code = Some(code);
 					task.as_mut().status = TaskStatus::TaskFinished;
 				} else {
 					panic!("unable to terminate idle task")
@@ -153,10 +413,203 @@ impl Scheduler {
 		self.reschedule();
 	}
 
+	/// Wait for the task `tid` to finish and return its exit code.
+	///
+	/// The exit code of a finished task is preserved until a `join` reads it,
+	/// even if the task finishes before it is joined: once the last resource of
+	/// a finished task is released its code is stashed in `FINISHED_CODES`, from
+	/// where a late `join` still returns it, unless the capped stash has evicted
+	/// it in the meantime (see `release_finished_task`). If the target is still live, the
+	/// caller is blocked and registered on the target's waiter list, and woken
+	/// once the target finishes.
+	pub unsafe fn join(&mut self, tid: TaskId) -> Option<i32> {
+		if tid == self.current_tid {
+			// a task cannot wait for itself
+			return None;
+		}
+
+		loop {
+			// the task may already have finished and been released; its exit
+			// code then lives on in FINISHED_CODES
+			if let Some(code) = FINISHED_CODES.lock().as_mut().unwrap().remove(&tid) {
+				return Some(code);
+			}
+
+			{
+				let mut guard = TASKS.lock();
+				let current = *guard.as_ref().unwrap().get(&self.current_tid).unwrap();
+
+				match guard.as_mut().unwrap().get_mut(&tid) {
+					Some(task) => {
+						match task.as_ref().status {
+							TaskStatus::TaskFinished => {
+								let code = task.as_ref().exit_code;
+
+								// drop ourselves from the waiter list; the last
+								// waiter releases the finished control block but
+								// preserves its exit code for any later join
+								task.as_mut().waiters.retain(|w| w.as_ref().id != self.current_tid);
+								if task.as_ref().waiters.is_empty() {
+									self.release_finished_task(task);
+								}
+
+								return code;
+							},
+							// already released, its code has been consumed
+							TaskStatus::TaskInvalid => return None,
+							_ => { task.as_mut().waiters.push_back(current); }
+						}
+					},
+					// the task is already gone, there is nothing to wait for
+					None => return None
+				}
+			}
+
+			debug!("task {} joins task {}", self.current_tid, tid);
+			self.block_current_task();
+			self.reschedule();
+		}
+	}
+
+	/// Request termination of the task `tid`, no matter which state it is in.
+	///
+	/// A ready or sleeping target is pulled out of its queue and finished right
+	/// away; a blocked target is finished in place. A running target (on this
+	/// or another core) only has its `kill_pending` flag raised and terminates
+	/// itself at the next `schedule` entry, so that the stack it is currently
+	/// executing on is never freed from underneath it.
+	///
+	/// Finishing a target releases its control block, so every queue that can
+	/// hold a reference to it must first drop that reference. This is why
+	/// finishing a blocked target is only sound for the wait states this
+	/// scheduler itself owns: a task blocked in `join` is scrubbed from every
+	/// waiter list (see `finish_task`), and an async task parked in
+	/// `async_task_entry` is reached only through a `Waker`, which resolves its
+	/// target by id against the live-task table and so cannot dereference a
+	/// released block. A future wait primitive that parks a `Shared<Task>` on a
+	/// queue of its own would have to be scrubbed here as well.
+	pub unsafe fn kill(&mut self, tid: TaskId) {
+		let target = match TASKS.lock().as_ref().unwrap().get(&tid) {
+			Some(task) => *task,
+			None => {
+				info!("unable to kill unknown task {}", tid);
+				return;
+			}
+		};
+
+		let status = target.as_ref().status;
+		if status == TaskStatus::TaskIdle
+			|| status == TaskStatus::TaskFinished
+			|| status == TaskStatus::TaskInvalid {
+			return;
+		}
+
+		debug!("kill task {}", tid);
+		target.as_mut().kill_pending = true;
+
+		match status {
+			// the running task observes the flag itself and self-terminates
+			TaskStatus::TaskRunning => {},
+			TaskStatus::TaskReady => {
+				self.remove_from_ready_queues(tid);
+				self.finish_task(target);
+			},
+			TaskStatus::TaskSleeping => {
+				self.remove_from_timer_queue(target);
+				self.finish_task(target);
+			},
+			TaskStatus::TaskBlocked => {
+				self.finish_task(target);
+			},
+			_ => {}
+		}
+	}
+
+	/// Finish a task that is not the running one, as the result of a `kill`.
+	///
+	/// This mirrors the teardown `exit_with` performs for a self-terminating
+	/// task: the local-storage destructors run and any boxed future is released
+	/// before the control block is flagged as finished. The dying task is
+	/// dropped from every other waiter list it might still be registered on, so
+	/// that a task killed while blocked in `join` cannot be woken through a
+	/// dangling pointer afterwards. Waiters of the killed task are woken to copy
+	/// out the exit code; if there are none, the block is released right away
+	/// while its exit code is preserved for a join that may still arrive.
+	unsafe fn finish_task(&mut self, mut task: Shared<Task>) {
+		let id = task.as_ref().id;
+
+		self.drop_task_locals(id);
+		self.drop_async_state(id);
+		self.remove_from_all_waiters(id);
+
+		if task.as_ref().exit_code.is_none() {
+			task.as_mut().exit_code = Some(-1);
+		}
+		task.as_mut().status = TaskStatus::TaskFinished;
+
+		if task.as_ref().waiters.is_empty() {
+			// nobody is waiting yet: release the block right away but keep the
+			// exit code for a join that may still arrive
+			self.release_finished_task(&mut task);
+		} else {
+			for waiter in task.as_ref().waiters.clone() {
+				self.wakeup_task(waiter);
+			}
+		}
+	}
+
+	/// Hand a finished task's control block to the deferred-release path,
+	/// preserving its exit code in `FINISHED_CODES` so that a `join` arriving
+	/// after the block has been reclaimed still returns the code.
+	///
+	/// A code is only collected from the stash by a matching `join` (or cleared
+	/// when the id is reused by `spawn`), so a finished task that is never
+	/// joined would otherwise pin its entry for ever. Cap the stash at
+	/// `MAX_TASKS` entries and evict the oldest id once it is full, bounding the
+	/// memory it can hold at the cost of returning `None` from a very late join.
+	unsafe fn release_finished_task(&self, task: &mut Shared<Task>) {
+		let id = task.as_ref().id;
+		let code = task.as_ref().exit_code.unwrap_or(-1);
+
+		{
+			let mut guard = FINISHED_CODES.lock();
+			let codes = guard.as_mut().unwrap();
+			if codes.len() >= MAX_TASKS {
+				if let Some(&oldest) = codes.keys().next() {
+					codes.remove(&oldest);
+				}
+			}
+			codes.insert(id, code);
+		}
+
+		task.as_mut().status = TaskStatus::TaskInvalid;
+		FINISHED_TASKS.lock().as_mut().unwrap().push_back(id);
+	}
+
+	/// Remove the task `tid` from every task's `join` waiter list, so that a
+	/// dying task that is itself blocked in `join` leaves no dangling pointer
+	/// behind.
+	unsafe fn remove_from_all_waiters(&mut self, tid: TaskId) {
+		let mut guard = TASKS.lock();
+		for task in guard.as_mut().unwrap().values_mut() {
+			task.as_mut().waiters.retain(|w| w.as_ref().id != tid);
+		}
+	}
+
+	/// Remove a task from every core's ready queues.
+	unsafe fn remove_from_ready_queues(&mut self, tid: TaskId) {
+		for core in 0..NO_CORES.load(Ordering::SeqCst) {
+			let mut guard = scheduler(core).ready_queues.lock();
+			for prio in 0..NO_PRIORITIES {
+				guard[prio].remove(tid);
+			}
+		}
+	}
+
 	pub unsafe fn block_current_task(&mut self) -> Shared<Task> {
 		let id = self.current_tid;
 
-		match self.tasks.lock().as_mut().unwrap().get_mut(&id) {
+		match TASKS.lock().as_mut().unwrap().get_mut(&id) {
 			Some(task) => {
 				if task.as_ref().status == TaskStatus::TaskRunning {
 					debug!("block task {}", id);
@@ -171,17 +624,207 @@ impl Scheduler {
 		}
 	}
 
+	/// Wake the task `tid` if it is still live. The task is looked up in the
+	/// shared table first, so a waker that outlived its task (its control block
+	/// has since been released) resolves to nothing instead of dereferencing a
+	/// dangling pointer.
+	pub unsafe fn wakeup_task_by_id(&mut self, tid: TaskId) {
+		let task = match TASKS.lock().as_ref().unwrap().get(&tid) {
+			Some(task) => *task,
+			None => return
+		};
+
+		self.wakeup_task(task);
+	}
+
 	pub unsafe fn wakeup_task(&mut self, mut task: Shared<Task>) {
-		if task.as_ref().status == TaskStatus::TaskBlocked {
+		let status = task.as_ref().status;
+
+		if status == TaskStatus::TaskBlocked || status == TaskStatus::TaskSleeping {
 			let prio = task.as_ref().prio;
 
 			debug!("wakeup task {}", task.as_ref().id);
 
+			// a task woken explicitly ahead of its deadline must leave the
+			// timer queue, otherwise the timer would enqueue it a second time
+			if status == TaskStatus::TaskSleeping {
+				self.remove_from_timer_queue(task);
+			}
+
 			task.as_mut().status = TaskStatus::TaskReady;
 			self.ready_queues.lock()[prio.into() as usize].push_back(&mut Shared::new_unchecked(task.as_mut()));
 		}
 	}
 
+	/// Block the running task until at least `ticks` timer ticks have elapsed.
+	pub unsafe fn sleep(&mut self, ticks: u64) {
+		let id = self.current_tid;
+		let deadline = TICKS.load(Ordering::SeqCst) + ticks;
+
+		let task = {
+			let mut guard = TASKS.lock();
+			match guard.as_mut().unwrap().get_mut(&id) {
+				Some(task) => {
+					if task.as_ref().status == TaskStatus::TaskRunning {
+						debug!("task {} sleeps until tick {}", id, deadline);
+						task.as_mut().status = TaskStatus::TaskSleeping;
+						*task
+					} else {
+						panic!("unable to put task {} to sleep", id);
+					}
+				},
+				None => panic!("unable to put task {} to sleep", id)
+			}
+		};
+
+		SLEEPING_TASKS.lock().as_mut().unwrap()
+			.entry(deadline).or_insert_with(VecDeque::new).push_back(task);
+
+		self.reschedule();
+	}
+
+	/// Remove a task from the timer queue, e.g. when it is woken before its
+	/// deadline is due.
+	unsafe fn remove_from_timer_queue(&mut self, target: Shared<Task>) {
+		let tid = target.as_ref().id;
+		let mut guard = SLEEPING_TASKS.lock();
+
+		if let Some(map) = guard.as_mut() {
+			let mut empty: VecDeque<u64> = VecDeque::new();
+
+			for (deadline, queue) in map.iter_mut() {
+				queue.retain(|t| t.as_ref().id != tid);
+				if queue.is_empty() {
+					empty.push_back(*deadline);
+				}
+			}
+
+			for deadline in empty {
+				map.remove(&deadline);
+			}
+		}
+	}
+
+	/// Called by the architecture's timer IRQ handler on every tick. Charges
+	/// one tick against the running task's time slice and, once the slice is
+	/// exhausted, flags the task for rescheduling. The switch itself must not
+	/// happen from within the IRQ (the lock state has to be clean), so we only
+	/// raise `reschedule_pending` here and let `check_preemption` act on it on
+	/// the IRQ return path.
+	pub unsafe fn timer_tick(&mut self) {
+		let now = TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+		let id = self.current_tid;
+
+		if let Some(task) = TASKS.lock().as_mut().unwrap().get_mut(&id) {
+			if task.as_ref().status == TaskStatus::TaskRunning {
+				if task.as_ref().quantum_remaining > 0 {
+					task.as_mut().quantum_remaining -= 1;
+				}
+
+				// keep flagging an exhausted slice, so that a peer which
+				// becomes ready later still gets its turn
+				if task.as_ref().quantum_remaining == 0 {
+					self.reschedule_pending.store(true, Ordering::SeqCst);
+				}
+			}
+		}
+
+		// move every task whose deadline is due back onto its ready queue
+		let due = {
+			let mut guard = SLEEPING_TASKS.lock();
+			match guard.as_mut() {
+				Some(map) => {
+					let remainder = map.split_off(&(now + 1));
+					mem::replace(map, remainder)
+				},
+				None => return
+			}
+		};
+
+		for (_, queue) in due {
+			if !queue.is_empty() {
+				self.reschedule_pending.store(true, Ordering::SeqCst);
+			}
+
+			for task in queue {
+				self.wakeup_task(task);
+			}
+		}
+	}
+
+	/// Invoked on the IRQ return path, after `irq_nested_enable`. If the timer
+	/// flagged the running task for preemption, give up the CPU now that the
+	/// interrupt frame is unwound.
+	pub unsafe fn check_preemption(&mut self) {
+		if self.reschedule_pending.swap(false, Ordering::SeqCst) {
+			self.reschedule();
+		}
+	}
+
+	/// Store `value` in the local-storage slot `key` of the running task.
+	pub unsafe fn set_task_local(&mut self, key: usize, value: usize) {
+		if key >= TASK_LOCAL_SLOTS {
+			panic!("task local key {} out of range", key);
+		}
+
+		let id = self.current_tid;
+		if let Some(task) = TASKS.lock().as_mut().unwrap().get_mut(&id) {
+			task.as_mut().local_storage[key] = value;
+		}
+	}
+
+	/// Read the local-storage slot `key` of the running task. Slots default to
+	/// zero, so an unset key reads back as `0`.
+	pub unsafe fn get_task_local(&self, key: usize) -> usize {
+		if key >= TASK_LOCAL_SLOTS {
+			panic!("task local key {} out of range", key);
+		}
+
+		let id = self.current_tid;
+		match TASKS.lock().as_ref().unwrap().get(&id) {
+			Some(task) => task.as_ref().local_storage[key],
+			None => 0
+		}
+	}
+
+	/// Register a destructor for the local-storage slot `key` of the running
+	/// task. It is called with the slot's value when the task exits, so a slot
+	/// that owns an allocation can release it.
+	pub unsafe fn set_task_local_dtor(&mut self, key: usize, dtor: extern fn(usize)) {
+		if key >= TASK_LOCAL_SLOTS {
+			panic!("task local key {} out of range", key);
+		}
+
+		let id = self.current_tid;
+		if let Some(task) = TASKS.lock().as_mut().unwrap().get_mut(&id) {
+			task.as_mut().local_destructors[key] = Some(dtor);
+		}
+	}
+
+	/// Run and clear the local-storage destructors of the task `id`.
+	unsafe fn drop_task_locals(&mut self, id: TaskId) {
+		let mut pending: [Option<(extern fn(usize), usize)>; TASK_LOCAL_SLOTS] = [None; TASK_LOCAL_SLOTS];
+		{
+			let mut guard = TASKS.lock();
+			if let Some(task) = guard.as_mut().unwrap().get_mut(&id) {
+				for key in 0..TASK_LOCAL_SLOTS {
+					if let Some(dtor) = task.as_ref().local_destructors[key] {
+						pending[key] = Some((dtor, task.as_ref().local_storage[key]));
+						task.as_mut().local_destructors[key] = None;
+						task.as_mut().local_storage[key] = 0;
+					}
+				}
+			}
+		}
+
+		// invoke the destructors outside the lock; they may touch the scheduler
+		for slot in pending.iter() {
+			if let Some((dtor, value)) = *slot {
+				dtor(value);
+			}
+		}
+	}
+
 	#[inline(always)]
 	pub fn get_current_taskid(&self) -> TaskId {
 		self.current_tid
@@ -190,7 +833,7 @@ impl Scheduler {
 	pub fn get_priority(&self, tid: TaskId) -> Priority {
 		let mut prio: Priority = NORMAL_PRIO;
 
-		match self.tasks.lock().as_ref().unwrap().get(&tid) {
+		match TASKS.lock().as_ref().unwrap().get(&tid) {
 			Some(task) => prio = unsafe { task.as_ref().prio },
 			None => { info!("didn't find current task"); }
 		}
@@ -200,7 +843,7 @@ impl Scheduler {
 
 	unsafe fn get_next_task(&mut self) -> Option<Shared<Task>> {
 		let mut prio = NO_PRIORITIES as usize;
-		let mut tasks_guard = self.tasks.lock();
+		let mut tasks_guard = TASKS.lock();
 		let status: TaskStatus;
 
 		{
@@ -214,31 +857,116 @@ impl Scheduler {
 			status = current_task.as_ref().status;
 		}
 
-		let mut guard = self.ready_queues.lock();
+		{
+			let mut guard = self.ready_queues.lock();
 
-		for i in 0..prio {
-			match guard[i].pop_front() {
-				Some(mut task) => {
-					task.as_mut().status = TaskStatus::TaskRunning;
-					return Some(task)
-				},
-				None => {}
+			for i in 0..prio {
+				match guard[i].pop_front() {
+					Some(mut task) => {
+						task.as_mut().status = TaskStatus::TaskRunning;
+						return Some(task)
+					},
+					None => {}
+				}
 			}
 		}
 
 		if status != TaskStatus::TaskRunning {
-			// current task isn't able to run and no other task available
-			// => switch to the idle task
+			// all local queues are empty and the current task cannot run =>
+			// try to pull work off a sibling core before going idle
+			if let Some(mut task) = self.steal_tasks() {
+				task.as_mut().status = TaskStatus::TaskRunning;
+				return Some(task);
+			}
+
+			// nothing to steal either => switch to the idle task
 			return Some(*tasks_guard.as_mut().unwrap().get(&self.idle_tid).unwrap());
 		}
 
 		None
 	}
 
+	/// Steal roughly half of the highest-priority non-empty ready queue from a
+	/// sibling core. The scan starts one core past this one (round-robin) so
+	/// that idle cores do not all hammer core 0. Returns one ready task to run
+	/// immediately; the rest are moved onto this core's ready queues.
+	unsafe fn steal_tasks(&mut self) -> Option<Shared<Task>> {
+		let ncores = NO_CORES.load(Ordering::SeqCst);
+		let me = core_id();
+
+		for offset in 1..ncores {
+			let victim = (me + offset) % ncores;
+
+			for prio in 0..NO_PRIORITIES {
+				// collect the stolen tasks while holding only the victim's
+				// lock, so we never hold two ready-queue locks at once
+				let mut stolen: VecDeque<Shared<Task>> = VecDeque::new();
+				{
+					let mut vguard = scheduler(victim).ready_queues.lock();
+					let count = vguard[prio].len();
+					if count == 0 {
+						continue;
+					}
+
+					let steal = (count + 1) / 2;
+					for _ in 0..steal {
+						match vguard[prio].pop_front() {
+							Some(task) => stolen.push_back(task),
+							None => break
+						}
+					}
+				}
+
+				debug!("core {} steals {} task(s) from core {}", me, stolen.len(), victim);
+
+				// keep the first task to run ourselves, enqueue the rest
+				let next = stolen.pop_front();
+				{
+					let mut guard = self.ready_queues.lock();
+					for mut task in stolen {
+						guard[prio].push_back(&mut task);
+					}
+				}
+
+				return next;
+			}
+		}
+
+		None
+	}
+
 	pub unsafe fn schedule(&mut self) {
 		let old_id: TaskId = self.current_tid;
 		let mut new_stack_pointer: u64 = 0;
 
+		// a running task that has been flagged by `kill` terminates itself
+		// here, so that its still-executing stack is only released later via
+		// the deferred `finished_tasks` path in `cleanup_tasks`
+		let self_killed = {
+			let guard = TASKS.lock();
+			match guard.as_ref().unwrap().get(&old_id) {
+				Some(task) => task.as_ref().kill_pending
+					&& task.as_ref().status == TaskStatus::TaskRunning,
+				None => false
+			}
+		};
+		if self_killed {
+			// mirror the `exit_with` teardown before flagging the task as
+			// finished, so that a killed task releases its resources just like
+			// one that exits on its own
+			self.drop_task_locals(old_id);
+			self.drop_async_state(old_id);
+			self.remove_from_all_waiters(old_id);
+
+			let mut guard = TASKS.lock();
+			if let Some(task) = guard.as_mut().unwrap().get_mut(&old_id) {
+				if task.as_ref().exit_code.is_none() {
+					task.as_mut().exit_code = Some(-1);
+				}
+				task.as_mut().status = TaskStatus::TaskFinished;
+			}
+		}
+
 		// do we have a task, which is ready?
 		match self.get_next_task() {
 			Some(mut task_shared) => {
@@ -253,25 +981,36 @@ impl Scheduler {
 		// do we have to switch to a new task?
 		if old_id != self.current_tid {
 			let old_stack_pointer: *const u64;
+			let mut waiters_to_wake: VecDeque<Shared<Task>> = VecDeque::new();
 
 			{
 				// destroy guard before context switch
-				let mut guard = self.tasks.lock();
+				let mut guard = TASKS.lock();
 				let task = guard.as_mut().unwrap().get_mut(&old_id).unwrap();
 
 				if task.as_ref().status == TaskStatus::TaskRunning {
 					task.as_mut().status = TaskStatus::TaskReady;
 					self.ready_queues.lock()[task.as_ref().prio.into() as usize].push_back(&mut Shared::new_unchecked(task.as_mut()));
 				} else if task.as_ref().status == TaskStatus::TaskFinished {
-					task.as_mut().status = TaskStatus::TaskInvalid;
-					// release the task later, because the stack is required
-					// to call the function "switch"
-					// => push id to a queue and release the task later
-					self.finished_tasks.lock().as_mut().unwrap().push_back(old_id);
+					if task.as_ref().waiters.is_empty() {
+						// the task finished before it was joined: release its
+						// control block through the deferred path so it does not
+						// leak, but preserve the exit code for a late join.
+						self.release_finished_task(task);
+					} else {
+						// a join is already waiting; keep the block alive so the
+						// last waiter releases it after copying the exit code.
+						waiters_to_wake = task.as_ref().waiters.clone();
+					}
 				}
 				old_stack_pointer = &task.as_ref().last_stack_pointer;
 			}
 
+			// wake every task blocked in `join` on the finished task
+			for waiter in waiters_to_wake {
+				self.wakeup_task(waiter);
+			}
+
 			debug!("switch task from {} to {}", old_id, self.current_tid);
 
 			switch(old_stack_pointer, new_stack_pointer);
@@ -281,9 +1020,9 @@ impl Scheduler {
 	unsafe fn cleanup_tasks(&mut self)
 	{
 		// do we have finished tasks? => drop first tasks => deallocate implicitly the stack
-		match self.finished_tasks.lock().as_mut().unwrap().pop_front() {
+		match FINISHED_TASKS.lock().as_mut().unwrap().pop_front() {
 			Some(id) => {
-				match self.tasks.lock().as_mut().unwrap().remove(&id) {
+				match TASKS.lock().as_mut().unwrap().remove(&id) {
 					Some(task) => drop(Box::from_raw(task.as_ptr())),
 					None => info!("unable to drop task {}", id)
 				}
@@ -0,0 +1,305 @@
+// Copyright (c) 2017 Stefan Lankes, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Architecture independent task control block
+
+use core::fmt;
+use core::future::Future;
+use core::mem;
+use core::ptr::Shared;
+use core::task::Waker;
+use alloc::VecDeque;
+use alloc::boxed::Box;
+use alloc::alloc::{alloc_zeroed, Layout};
+use consts::*;
+use scheduler::do_exit;
+use scheduler::scheduler::SchedulerError;
+
+/// The identifier of a task is a `usize` wrapped in a newtype, so that it can
+/// never be confused with an arbitrary integer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(usize);
+
+impl TaskId {
+	pub const fn into(&self) -> usize {
+		self.0
+	}
+
+	pub const fn from(id: usize) -> TaskId {
+		TaskId(id)
+	}
+}
+
+impl fmt::Display for TaskId {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+/// Priority of a task, from 0 (highest) to `NO_PRIORITIES - 1` (lowest).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Priority(u8);
+
+impl Priority {
+	pub const fn into(&self) -> u8 {
+		self.0
+	}
+
+	pub const fn from(prio: u8) -> Priority {
+		Priority(prio)
+	}
+}
+
+impl fmt::Display for Priority {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0)
+	}
+}
+
+/// Lifecycle of a task.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TaskStatus {
+	TaskInvalid,
+	TaskReady,
+	TaskRunning,
+	TaskBlocked,
+	TaskSleeping,
+	TaskFinished,
+	TaskIdle
+}
+
+/// Number of per-task local-storage slots every task is born with.
+pub const TASK_LOCAL_SLOTS: usize = 8;
+
+/// Number of timer ticks a task of the given priority may run before it is
+/// preempted in favour of an equal-priority peer. Higher priorities (lower
+/// numbers) receive a slightly larger time slice.
+pub fn quantum_for(prio: Priority) -> u32 {
+	(NO_PRIORITIES as u32 - prio.into() as u32) * TIMESLICE
+}
+
+/// A kernel stack, allocated on the heap and aligned to a page boundary.
+#[repr(align(4096))]
+pub struct Stack {
+	buffer: [u8; KERNEL_STACK_SIZE]
+}
+
+impl Stack {
+	/// Allocate a zeroed kernel stack, returning `None` if the heap is
+	/// exhausted.
+	pub fn try_new() -> Option<Box<Stack>> {
+		unsafe {
+			let layout = Layout::new::<Stack>();
+			let ptr = alloc_zeroed(layout) as *mut Stack;
+
+			if ptr.is_null() {
+				None
+			} else {
+				Some(Box::from_raw(ptr))
+			}
+		}
+	}
+
+	pub fn top(&self) -> usize {
+		(&(self.buffer[KERNEL_STACK_SIZE - 1]) as *const _) as usize
+	}
+
+	pub fn bottom(&self) -> usize {
+		(&(self.buffer[0]) as *const _) as usize
+	}
+}
+
+/// The state of all registers that `switch` saves and restores for a task.
+#[repr(C, packed)]
+struct State {
+	/// GS segment base
+	gs: u64,
+	/// FS segment base
+	fs: u64,
+	r15: u64,
+	r14: u64,
+	r13: u64,
+	r12: u64,
+	r11: u64,
+	r10: u64,
+	r9: u64,
+	r8: u64,
+	rdi: u64,
+	rsi: u64,
+	rbp: u64,
+	rbx: u64,
+	rdx: u64,
+	rcx: u64,
+	rax: u64,
+	/// status flags
+	rflags: u64,
+	/// instruction pointer
+	rip: u64
+}
+
+/// A task control block holds everything the scheduler needs to manage a task.
+pub struct Task {
+	/// unique identifier of this task
+	pub id: TaskId,
+	/// current status of the task
+	pub status: TaskStatus,
+	/// scheduling priority of the task
+	pub prio: Priority,
+	/// timer ticks this task may still run before being preempted
+	pub quantum_remaining: u32,
+	/// last stack pointer before a context switch to another task
+	pub last_stack_pointer: u64,
+	/// exit code, set once the task has finished
+	pub exit_code: Option<i32>,
+	/// set by `kill` to request that this task terminates
+	pub kill_pending: bool,
+	/// tasks blocked in `join`, waiting for this task to finish
+	pub waiters: VecDeque<Shared<Task>>,
+	/// per-task local-storage slots, keyed by a small integer
+	pub local_storage: [usize; TASK_LOCAL_SLOTS],
+	/// optional destructor for each slot, run in `exit` when the task ends
+	pub local_destructors: [Option<extern fn(usize)>; TASK_LOCAL_SLOTS],
+	/// boxed future driven by the async poll loop, `None` for plain tasks
+	pub future: Option<*mut (Future<Output = ()> + 'static)>,
+	/// waker handed to the future, so I/O and timer sources can wake this task
+	pub waker: Option<Waker>,
+	/// the kernel stack of the task, allocated lazily in `create_stack_frame`
+	pub stack: Option<Box<Stack>>
+}
+
+impl Task {
+	pub fn new(id: TaskId, status: TaskStatus, prio: Priority) -> Task {
+		Task {
+			id: id,
+			status: status,
+			prio: prio,
+			quantum_remaining: quantum_for(prio),
+			last_stack_pointer: 0,
+			exit_code: None,
+			kill_pending: false,
+			waiters: VecDeque::new(),
+			local_storage: [0; TASK_LOCAL_SLOTS],
+			local_destructors: [None; TASK_LOCAL_SLOTS],
+			future: None,
+			waker: None,
+			stack: None
+		}
+	}
+
+	/// Ensure the task owns a kernel stack, allocating one on first use.
+	pub fn allocate_stack(&mut self) -> Result<(), SchedulerError> {
+		if self.stack.is_none() {
+			self.stack = Some(Stack::try_new().ok_or(SchedulerError::StackAllocationFailed)?);
+		}
+
+		Ok(())
+	}
+
+	/// Refill the time slice, e.g. when the task is (re-)enqueued on a ready
+	/// queue after having given up the CPU.
+	pub fn refill_quantum(&mut self) {
+		self.quantum_remaining = quantum_for(self.prio);
+	}
+
+	/// Prepare the stack so that the first context switch into this task starts
+	/// executing `func`. When `func` returns, control falls through to
+	/// `leave_task`, which terminates the task cleanly. Fails if the kernel
+	/// stack cannot be allocated.
+	pub fn create_stack_frame(&mut self, func: extern fn()) -> Result<(), SchedulerError> {
+		self.allocate_stack()?;
+
+		unsafe {
+			let mut stack: *mut u64 = (self.stack.as_ref().unwrap().top()) as *mut u64;
+
+			// the first value is read by `leave_task` as the return address
+			*stack = 0xDEAD_BEEFu64;
+			stack = (stack as usize - mem::size_of::<u64>()) as *mut u64;
+			*stack = leave_task as u64;
+
+			stack = (stack as usize - mem::size_of::<State>()) as *mut u64;
+
+			let state: *mut State = stack as *mut State;
+			mem::write_bytes(state, 0, 1);
+
+			(*state).rip = func as u64;
+			(*state).rflags = 0x1202u64;
+
+			self.last_stack_pointer = stack as u64;
+		}
+
+		Ok(())
+	}
+}
+
+/// Entry trampoline: after the task function returns we end up here and exit.
+extern fn leave_task() -> ! {
+	do_exit();
+
+	loop {}
+}
+
+/// A priority ready queue, holding pointers to the tasks waiting to run.
+///
+/// The queue is lazily allocated so that it can be created in a `const`
+/// context before the heap is available.
+pub struct TaskQueue {
+	queue: Option<VecDeque<Shared<Task>>>
+}
+
+impl TaskQueue {
+	pub const fn new() -> TaskQueue {
+		TaskQueue { queue: None }
+	}
+
+	pub fn push_back(&mut self, task: &mut Shared<Task>) {
+		// a task that becomes runnable again is granted a fresh time slice
+		unsafe { task.as_mut().refill_quantum(); }
+
+		if self.queue.is_none() {
+			self.queue = Some(VecDeque::new());
+		}
+
+		self.queue.as_mut().unwrap().push_back(*task);
+	}
+
+	pub fn pop_front(&mut self) -> Option<Shared<Task>> {
+		match self.queue {
+			Some(ref mut queue) => queue.pop_front(),
+			None => None
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		match self.queue {
+			Some(ref queue) => queue.len(),
+			None => 0
+		}
+	}
+
+	/// Remove the task `tid` from this queue, if present.
+	pub fn remove(&mut self, tid: TaskId) {
+		if let Some(ref mut queue) = self.queue {
+			queue.retain(|task| unsafe { task.as_ref().id } != tid);
+		}
+	}
+}